@@ -1,208 +1,1331 @@
 //! Visitor for page tables.
 
-use crate::structures::paging::{
-    frame::PhysFrame,
-    page::Size4KiB,
-    page_table::{FrameError, PageTableEntry},
-    Page, PageTable,
+use crate::{
+    structures::paging::{
+        frame::PhysFrame,
+        page::{PageRange, Size1GiB, Size2MiB, Size4KiB},
+        page_table::{FrameError, PageTableEntry, PageTableFlags},
+        FrameAllocator, FrameDeallocator, Page, PageTable,
+    },
+    PhysAddr, VirtAddr,
 };
 
+/// The number of bits each page table level covers.
+#[cfg(feature = "la57")]
+const PML5_SHIFT: u32 = 48;
+const PML4_SHIFT: u32 = 39;
+const PDPT_SHIFT: u32 = 30;
+const PD_SHIFT: u32 = 21;
+const PT_SHIFT: u32 = 12;
+
+/// Combines the virtual address accumulated so far by the walk (`base`) with the index of the
+/// entry about to be descended into at the given level, returning the (possibly partial) virtual
+/// address covered by that entry.
+fn index_to_vaddr(base: VirtAddr, shift: u32, index: usize) -> VirtAddr {
+    VirtAddr::new_truncate(base.as_u64() | ((index as u64) << shift))
+}
+
+/// Like [`index_to_vaddr`], but for the PML5 level: `VirtAddr::new_truncate` sign-extends from
+/// bit 47 for 48-bit canonical addresses, which would discard the PML5 index (bits 48..=56)
+/// entirely. Under `la57` addresses are canonical from bit 56 instead, so sign-extend from there.
+#[cfg(feature = "la57")]
+fn index_to_vaddr_la57(base: VirtAddr, index: usize) -> VirtAddr {
+    let raw = base.as_u64() | ((index as u64) << PML5_SHIFT);
+    unsafe { VirtAddr::new_unsafe(((raw << 7) as i64 >> 7) as u64) }
+}
+
+/// The 9-bit index into the page table at the given level that `addr` falls into.
+fn table_index(addr: VirtAddr, shift: u32) -> usize {
+    ((addr.as_u64() >> shift) & 0x1ff) as usize
+}
+
+/// Combines the flags of two levels of a page-table walk, following x86-64's actual walk
+/// semantics: permission bits (e.g. `WRITABLE`, `USER_ACCESSIBLE`) are ANDed, since the least
+/// permissive level wins, while `NO_EXECUTE` is ORed, since any level setting it makes the
+/// mapping non-executable.
+fn combine_flags(acc: PageTableFlags, next: PageTableFlags) -> PageTableFlags {
+    let nx = (acc | next) & PageTableFlags::NO_EXECUTE;
+    ((acc & next) & !PageTableFlags::NO_EXECUTE) | nx
+}
+
+/// The size of the page backing a mapping resolved by [`translate`], or installed by [`map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapPageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MapPageSize {
+    /// The size of a page of this size, in bytes.
+    fn bytes(self) -> u64 {
+        match self {
+            MapPageSize::Size4KiB => 0x1000,
+            MapPageSize::Size2MiB => 0x20_0000,
+            MapPageSize::Size1GiB => 0x4000_0000,
+        }
+    }
+}
+
 /// Visits all entries and levels of a page table heirarchy.
 pub trait PageTableVisit: Sized {
     fn get_page(&mut self, paddr: PhysFrame) -> Page<Size4KiB>;
 
+    /// Entry point for 5-level (LA57) paging, where `pml5` is the root table. Not used by
+    /// 4-level walkers, which start at [`Self::visit_pml4`] instead.
+    #[cfg(feature = "la57")]
+    fn visit_pml5(&mut self, pml5: &PageTable) {
+        visit_pml5(self, pml5)
+    }
+
+    #[cfg(feature = "la57")]
+    fn visit_pml5_entry(&mut self, index: usize, entry: &PageTableEntry, base: VirtAddr) {
+        visit_pml5_entry(self, index, entry, base)
+    }
+
     fn visit_pml4(&mut self, pml4: &PageTable) {
         visit_pml4(self, pml4)
     }
 
-    fn visit_pml4_entry(&mut self, entry: &PageTableEntry) {
-        visit_pml4_entry(self, entry)
+    fn visit_pml4_entry(&mut self, index: usize, entry: &PageTableEntry, base: VirtAddr) {
+        visit_pml4_entry(self, index, entry, base)
+    }
+
+    fn visit_pdpt(&mut self, pdpt: &PageTable, base: VirtAddr) {
+        visit_pdpt(self, pdpt, base)
+    }
+
+    fn visit_pdpt_entry(&mut self, index: usize, entry: &PageTableEntry, base: VirtAddr) {
+        visit_pdpt_entry(self, index, entry, base)
+    }
+
+    fn visit_pd(&mut self, pd: &PageTable, base: VirtAddr) {
+        visit_pd(self, pd, base)
+    }
+
+    fn visit_pd_entry(&mut self, index: usize, entry: &PageTableEntry, base: VirtAddr) {
+        visit_pd_entry(self, index, entry, base)
     }
 
-    fn visit_pdpt(&mut self, pdpt: &PageTable) {
-        visit_pdpt(self, pdpt)
+    fn visit_pt(&mut self, pt: &PageTable, base: VirtAddr) {
+        visit_pt(self, pt, base)
     }
 
-    fn visit_pdpt_entry(&mut self, entry: &PageTableEntry) {
-        visit_pdpt_entry(self, entry)
+    fn visit_pt_entry(&mut self, index: usize, entry: &PageTableEntry, base: VirtAddr) {
+        visit_pt_entry(self, index, entry, base)
     }
 
-    fn visit_pd(&mut self, pd: &PageTable) {
-        visit_pd(self, pd)
+    /// Called for every PDPT entry that maps a 1 GiB huge page.
+    fn visit_huge_page_1g(
+        &mut self,
+        _entry: &PageTableEntry,
+        _frame: PhysFrame<Size1GiB>,
+        _base: VirtAddr,
+    ) {
     }
 
-    fn visit_pd_entry(&mut self, entry: &PageTableEntry) {
-        visit_pd_entry(self, entry)
+    /// Called for every PD entry that maps a 2 MiB huge page.
+    fn visit_huge_page_2m(
+        &mut self,
+        _entry: &PageTableEntry,
+        _frame: PhysFrame<Size2MiB>,
+        _base: VirtAddr,
+    ) {
     }
 
-    fn visit_pt(&mut self, pt: &PageTable) {
-        visit_pt(self, pt)
+    /// Called for every PT entry that maps a 4 KiB page.
+    fn visit_page_4k(
+        &mut self,
+        _entry: &PageTableEntry,
+        _frame: PhysFrame<Size4KiB>,
+        _base: VirtAddr,
+    ) {
     }
 
-    fn visit_pt_entry(&mut self, entry: &PageTableEntry) {
-        visit_pt_entry(self, entry)
+    /// Walks the page tables rooted at `pml4` and translates `addr` to the physical address,
+    /// page size, and accumulated flags of the mapping that covers it, or `None` if `addr` is
+    /// not mapped.
+    ///
+    /// Always starts from `pml4`; under `la57` this does not consult [`Self::visit_pml5`], so it
+    /// only walks the 4-level hierarchy below the PML4 `pml4` points to.
+    fn translate(
+        &mut self,
+        pml4: &PageTable,
+        addr: VirtAddr,
+    ) -> Option<(PhysAddr, MapPageSize, PageTableFlags)> {
+        translate(self, pml4, addr)
+    }
+}
+
+/// Entry point for 5-level (LA57) paging, descending from the PML5 root into the PML4 reached
+/// by each present entry.
+#[cfg(feature = "la57")]
+pub fn visit_pml5<V: PageTableVisit>(visitor: &mut V, pml5: &PageTable) {
+    for (index, entry) in pml5.iter().enumerate() {
+        visitor.visit_pml5_entry(index, entry, VirtAddr::new(0))
+    }
+}
+
+#[cfg(feature = "la57")]
+pub fn visit_pml5_entry<V: PageTableVisit>(
+    visitor: &mut V,
+    index: usize,
+    entry: &PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr_la57(base, index);
+    match entry.frame() {
+        Ok(frame) => {
+            let pml4_page = visitor.get_page(frame);
+            let pml4 = unsafe { &*pml4_page.start_address().as_ptr() };
+            visit_pml4_at(visitor, pml4, base)
+        }
+        Err(FrameError::HugeFrame) => unreachable!(),
+        Err(FrameError::FrameNotPresent) => {}
     }
 }
 
 pub fn visit_pml4<V: PageTableVisit>(visitor: &mut V, pml4: &PageTable) {
-    pml4.iter()
-        .for_each(|entry| visitor.visit_pml4_entry(entry))
+    visit_pml4_at(visitor, pml4, VirtAddr::new(0))
 }
 
-pub fn visit_pml4_entry<V: PageTableVisit>(visitor: &mut V, entry: &PageTableEntry) {
+fn visit_pml4_at<V: PageTableVisit>(visitor: &mut V, pml4: &PageTable, base: VirtAddr) {
+    for (index, entry) in pml4.iter().enumerate() {
+        visitor.visit_pml4_entry(index, entry, base)
+    }
+}
+
+pub fn visit_pml4_entry<V: PageTableVisit>(
+    visitor: &mut V,
+    index: usize,
+    entry: &PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr(base, PML4_SHIFT, index);
     match entry.frame() {
         Ok(frame) => {
             let pdpt_page = visitor.get_page(frame);
             let pdpt = unsafe { &*pdpt_page.start_address().as_ptr() };
-            visitor.visit_pdpt(pdpt)
+            visitor.visit_pdpt(pdpt, base)
         }
         Err(FrameError::HugeFrame) => unreachable!(), // 512GB pages! Not yet :P
         Err(FrameError::FrameNotPresent) => {}
     }
 }
 
-pub fn visit_pdpt<V: PageTableVisit>(visitor: &mut V, pdpt: &PageTable) {
-    pdpt.iter()
-        .for_each(|entry| visitor.visit_pdpt_entry(entry))
+pub fn visit_pdpt<V: PageTableVisit>(visitor: &mut V, pdpt: &PageTable, base: VirtAddr) {
+    for (index, entry) in pdpt.iter().enumerate() {
+        visitor.visit_pdpt_entry(index, entry, base)
+    }
 }
 
-pub fn visit_pdpt_entry<V: PageTableVisit>(visitor: &mut V, entry: &PageTableEntry) {
+pub fn visit_pdpt_entry<V: PageTableVisit>(
+    visitor: &mut V,
+    index: usize,
+    entry: &PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr(base, PDPT_SHIFT, index);
     match entry.frame() {
         Ok(frame) => {
             let pd_page = visitor.get_page(frame);
             let pd = unsafe { &*pd_page.start_address().as_ptr() };
-            visitor.visit_pd(pd)
+            visitor.visit_pd(pd, base)
+        }
+        Err(FrameError::HugeFrame) => {
+            let frame = PhysFrame::<Size1GiB>::containing_address(entry.addr());
+            visitor.visit_huge_page_1g(entry, frame, base)
         }
-        Err(FrameError::HugeFrame) => {} // 1GB page
         Err(FrameError::FrameNotPresent) => {}
     }
 }
 
-pub fn visit_pd<V: PageTableVisit>(visitor: &mut V, pd: &PageTable) {
-    pd.iter().for_each(|entry| visitor.visit_pd_entry(entry))
+pub fn visit_pd<V: PageTableVisit>(visitor: &mut V, pd: &PageTable, base: VirtAddr) {
+    for (index, entry) in pd.iter().enumerate() {
+        visitor.visit_pd_entry(index, entry, base)
+    }
 }
 
-pub fn visit_pd_entry<V: PageTableVisit>(visitor: &mut V, entry: &PageTableEntry) {
+pub fn visit_pd_entry<V: PageTableVisit>(
+    visitor: &mut V,
+    index: usize,
+    entry: &PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr(base, PD_SHIFT, index);
     match entry.frame() {
         Ok(frame) => {
             let pt_page = visitor.get_page(frame);
             let pt = unsafe { &*pt_page.start_address().as_ptr() };
-            visitor.visit_pd(pt)
+            visitor.visit_pt(pt, base)
+        }
+        Err(FrameError::HugeFrame) => {
+            let frame = PhysFrame::<Size2MiB>::containing_address(entry.addr());
+            visitor.visit_huge_page_2m(entry, frame, base)
         }
-        Err(FrameError::HugeFrame) => {} // 2MB page
         Err(FrameError::FrameNotPresent) => {}
     }
 }
 
-pub fn visit_pt<V: PageTableVisit>(visitor: &mut V, pt: &PageTable) {
-    pt.iter().for_each(|entry| visitor.visit_pt_entry(entry))
+pub fn visit_pt<V: PageTableVisit>(visitor: &mut V, pt: &PageTable, base: VirtAddr) {
+    for (index, entry) in pt.iter().enumerate() {
+        visitor.visit_pt_entry(index, entry, base)
+    }
 }
 
-pub fn visit_pt_entry<V: PageTableVisit>(visitor: &mut V, entry: &PageTableEntry) {
+pub fn visit_pt_entry<V: PageTableVisit>(
+    visitor: &mut V,
+    index: usize,
+    entry: &PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr(base, PT_SHIFT, index);
     match entry.frame() {
-        Ok(frame) => {}                               // 4KB page
+        Ok(frame) => visitor.visit_page_4k(entry, frame, base),
         Err(FrameError::HugeFrame) => unreachable!(), // Not huge any more...
         Err(FrameError::FrameNotPresent) => {}
     }
 }
 
+/// Translates `addr` to a physical address by walking the page tables rooted at `pml4`,
+/// descending through intermediate tables reached via [`PageTableVisit::get_page`]. 4-level
+/// only: does not consult the PML5 level even under `la57`.
+pub fn translate<V: PageTableVisit>(
+    visitor: &mut V,
+    pml4: &PageTable,
+    addr: VirtAddr,
+) -> Option<(PhysAddr, MapPageSize, PageTableFlags)> {
+    let pml4_entry = pml4.iter().nth(table_index(addr, PML4_SHIFT))?;
+    let mut flags = pml4_entry.flags();
+    let pdpt_frame = pml4_entry.frame().ok()?;
+    let pdpt_page = visitor.get_page(pdpt_frame);
+    let pdpt: &PageTable = unsafe { &*pdpt_page.start_address().as_ptr() };
+
+    let pdpt_entry = pdpt.iter().nth(table_index(addr, PDPT_SHIFT))?;
+    flags = combine_flags(flags, pdpt_entry.flags());
+    if pdpt_entry
+        .flags()
+        .contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE)
+    {
+        let phys = pdpt_entry.addr() + (addr.as_u64() & 0x3fff_ffff);
+        return Some((phys, MapPageSize::Size1GiB, flags));
+    }
+    let pd_frame = pdpt_entry.frame().ok()?;
+    let pd_page = visitor.get_page(pd_frame);
+    let pd: &PageTable = unsafe { &*pd_page.start_address().as_ptr() };
+
+    let pd_entry = pd.iter().nth(table_index(addr, PD_SHIFT))?;
+    flags = combine_flags(flags, pd_entry.flags());
+    if pd_entry
+        .flags()
+        .contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE)
+    {
+        let phys = pd_entry.addr() + (addr.as_u64() & 0x1f_ffff);
+        return Some((phys, MapPageSize::Size2MiB, flags));
+    }
+    let pt_frame = pd_entry.frame().ok()?;
+    let pt_page = visitor.get_page(pt_frame);
+    let pt: &PageTable = unsafe { &*pt_page.start_address().as_ptr() };
+
+    let pt_entry = pt.iter().nth(table_index(addr, PT_SHIFT))?;
+    flags = combine_flags(flags, pt_entry.flags());
+    let frame = pt_entry.frame().ok()?;
+    let phys = frame.start_address() + (addr.as_u64() & 0xfff);
+    Some((phys, MapPageSize::Size4KiB, flags))
+}
+
 /// Mutably visits all entries and levels of a page table heirarchy.
 pub trait PageTableVisitMut: Sized {
     fn get_vaddr(&mut self, paddr: PhysAddr) -> VirtAddr;
 
+    /// Entry point for 5-level (LA57) paging, where `pml5` is the root table. Not used by
+    /// 4-level walkers, which start at [`Self::visit_pml4`] instead.
+    #[cfg(feature = "la57")]
+    fn visit_pml5(&mut self, pml5: &mut PageTable) {
+        visit_mut_pml5(self, pml5)
+    }
+
+    #[cfg(feature = "la57")]
+    fn visit_pml5_entry(&mut self, index: usize, entry: &mut PageTableEntry, base: VirtAddr) {
+        visit_mut_pml5_entry(self, index, entry, base)
+    }
+
     fn visit_pml4(&mut self, pml4: &mut PageTable) {
         visit_mut_pml4(self, pml4)
     }
 
-    fn visit_pml4_entry(&mut self, entry: &mut PageTableEntry) {
-        visit_mut_pml4_entry(self, entry)
+    fn visit_pml4_entry(&mut self, index: usize, entry: &mut PageTableEntry, base: VirtAddr) {
+        visit_mut_pml4_entry(self, index, entry, base)
+    }
+
+    fn visit_pdpt(&mut self, pdpt: &mut PageTable, base: VirtAddr) {
+        visit_mut_pdpt(self, pdpt, base)
+    }
+
+    fn visit_pdpt_entry(&mut self, index: usize, entry: &mut PageTableEntry, base: VirtAddr) {
+        visit_mut_pdpt_entry(self, index, entry, base)
+    }
+
+    fn visit_pd(&mut self, pd: &mut PageTable, base: VirtAddr) {
+        visit_mut_pd(self, pd, base)
+    }
+
+    fn visit_pd_entry(&mut self, index: usize, entry: &mut PageTableEntry, base: VirtAddr) {
+        visit_mut_pd_entry(self, index, entry, base)
     }
 
-    fn visit_pdpt(&mut self, pdpt: &mut PageTable) {
-        visit_mut_pdpt(self, pdpt)
+    fn visit_pt(&mut self, pt: &mut PageTable, base: VirtAddr) {
+        visit_mut_pt(self, pt, base)
     }
 
-    fn visit_pdpt_entry(&mut self, entry: &mut PageTableEntry) {
-        visit_mut_pdpt_entry(self, entry)
+    fn visit_pt_entry(&mut self, index: usize, entry: &mut PageTableEntry, base: VirtAddr) {
+        visit_mut_pt_entry(self, index, entry, base)
     }
 
-    fn visit_pd(&mut self, pd: &mut PageTable) {
-        visit_mut_pd(self, pd)
+    /// Called for every PDPT entry that maps a 1 GiB huge page.
+    fn visit_huge_page_1g(
+        &mut self,
+        _entry: &mut PageTableEntry,
+        _frame: PhysFrame<Size1GiB>,
+        _base: VirtAddr,
+    ) {
     }
 
-    fn visit_pd_entry(&mut self, entry: &mut PageTableEntry) {
-        visit_mut_pd_entry(self, entry)
+    /// Called for every PD entry that maps a 2 MiB huge page.
+    fn visit_huge_page_2m(
+        &mut self,
+        _entry: &mut PageTableEntry,
+        _frame: PhysFrame<Size2MiB>,
+        _base: VirtAddr,
+    ) {
     }
 
-    fn visit_pt(&mut self, pt: &mut PageTable) {
-        visit_mut_pt(self, pt)
+    /// Called for every PT entry that maps a 4 KiB page.
+    fn visit_page_4k(
+        &mut self,
+        _entry: &mut PageTableEntry,
+        _frame: PhysFrame<Size4KiB>,
+        _base: VirtAddr,
+    ) {
     }
 
-    fn visit_pt_entry(&mut self, entry: &mut PageTableEntry) {
-        visit_mut_pt_entry(self, entry)
+    /// Maps `range` to physical memory starting at `phys_start`, installing entries at `size`
+    /// granularity and creating any missing intermediate tables along the way by requesting
+    /// fresh, zeroed frames from `frame_alloc`.
+    ///
+    /// `pml4` is always treated as the 4-level root; under `la57` this does not install or walk
+    /// through a PML5 level.
+    fn map<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        pml4: &mut PageTable,
+        range: PageRange,
+        phys_start: PhysAddr,
+        flags: PageTableFlags,
+        size: MapPageSize,
+        frame_alloc: &mut A,
+    ) {
+        map(self, pml4, range, phys_start, flags, size, frame_alloc)
+    }
+
+    /// Clears every entry covering `range`, freeing intermediate tables that become empty back
+    /// to `frame_dealloc`.
+    ///
+    /// `pml4` is always treated as the 4-level root; under `la57` this does not walk through a
+    /// PML5 level.
+    fn unmap<D: FrameDeallocator<Size4KiB>>(
+        &mut self,
+        pml4: &mut PageTable,
+        range: PageRange,
+        frame_dealloc: &mut D,
+    ) {
+        unmap(self, pml4, range, frame_dealloc)
+    }
+}
+
+/// Entry point for 5-level (LA57) paging, descending from the PML5 root into the PML4 reached
+/// by each present entry.
+#[cfg(feature = "la57")]
+pub fn visit_mut_pml5<V: PageTableVisitMut>(visitor: &mut V, pml5: &mut PageTable) {
+    for (index, entry) in pml5.iter_mut().enumerate() {
+        visitor.visit_pml5_entry(index, entry, VirtAddr::new(0))
+    }
+}
+
+#[cfg(feature = "la57")]
+pub fn visit_mut_pml5_entry<V: PageTableVisitMut>(
+    visitor: &mut V,
+    index: usize,
+    entry: &mut PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr_la57(base, index);
+    match entry.frame() {
+        Ok(frame) => {
+            let pml4_vaddr = visitor.get_vaddr(frame.start_address());
+            let pml4 = unsafe { &mut *pml4_vaddr.as_mut_ptr() };
+            visit_mut_pml4_at(visitor, pml4, base)
+        }
+        Err(FrameError::HugeFrame) => unreachable!(),
+        Err(FrameError::FrameNotPresent) => {}
     }
 }
 
 pub fn visit_mut_pml4<V: PageTableVisitMut>(visitor: &mut V, pml4: &mut PageTable) {
-    pml4.iter_mut()
-        .for_each(|entry| visitor.visit_pml4_entry(entry))
+    visit_mut_pml4_at(visitor, pml4, VirtAddr::new(0))
+}
+
+fn visit_mut_pml4_at<V: PageTableVisitMut>(visitor: &mut V, pml4: &mut PageTable, base: VirtAddr) {
+    for (index, entry) in pml4.iter_mut().enumerate() {
+        visitor.visit_pml4_entry(index, entry, base)
+    }
 }
 
-pub fn visit_mut_pml4_entry<V: PageTableVisitMut>(visitor: &mut V, entry: &mut PageTableEntry) {
+pub fn visit_mut_pml4_entry<V: PageTableVisitMut>(
+    visitor: &mut V,
+    index: usize,
+    entry: &mut PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr(base, PML4_SHIFT, index);
     match entry.frame() {
         Ok(frame) => {
             let pdpt_vaddr = visitor.get_vaddr(frame.start_address());
             let pdpt = unsafe { &mut *pdpt_vaddr.as_mut_ptr() };
-            visitor.visit_pdpt(pdpt)
+            visitor.visit_pdpt(pdpt, base)
         }
         Err(FrameError::HugeFrame) => unreachable!(), // 512GB pages! Not yet :P
         Err(FrameError::FrameNotPresent) => {}
     }
 }
 
-pub fn visit_mut_pdpt<V: PageTableVisitMut>(visitor: &mut V, pdpt: &mut PageTable) {
-    pdpt.iter_mut()
-        .for_each(|entry| visitor.visit_pdpt_entry(entry))
+pub fn visit_mut_pdpt<V: PageTableVisitMut>(visitor: &mut V, pdpt: &mut PageTable, base: VirtAddr) {
+    for (index, entry) in pdpt.iter_mut().enumerate() {
+        visitor.visit_pdpt_entry(index, entry, base)
+    }
 }
 
-pub fn visit_mut_pdpt_entry<V: PageTableVisitMut>(visitor: &mut V, entry: &mut PageTableEntry) {
+pub fn visit_mut_pdpt_entry<V: PageTableVisitMut>(
+    visitor: &mut V,
+    index: usize,
+    entry: &mut PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr(base, PDPT_SHIFT, index);
     match entry.frame() {
         Ok(frame) => {
             let pd_vaddr = visitor.get_vaddr(frame.start_address());
             let pd = unsafe { &mut *pd_vaddr.as_mut_ptr() };
-            visitor.visit_pd(pd)
+            visitor.visit_pd(pd, base)
+        }
+        Err(FrameError::HugeFrame) => {
+            let frame = PhysFrame::<Size1GiB>::containing_address(entry.addr());
+            visitor.visit_huge_page_1g(entry, frame, base)
         }
-        Err(FrameError::HugeFrame) => {} // 1GB page
         Err(FrameError::FrameNotPresent) => {}
     }
 }
 
-pub fn visit_mut_pd<V: PageTableVisitMut>(visitor: &mut V, pd: &mut PageTable) {
-    pd.iter_mut()
-        .for_each(|entry| visitor.visit_pd_entry(entry))
+pub fn visit_mut_pd<V: PageTableVisitMut>(visitor: &mut V, pd: &mut PageTable, base: VirtAddr) {
+    for (index, entry) in pd.iter_mut().enumerate() {
+        visitor.visit_pd_entry(index, entry, base)
+    }
 }
 
-pub fn visit_mut_pd_entry<V: PageTableVisitMut>(visitor: &mut V, entry: &mut PageTableEntry) {
+pub fn visit_mut_pd_entry<V: PageTableVisitMut>(
+    visitor: &mut V,
+    index: usize,
+    entry: &mut PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr(base, PD_SHIFT, index);
     match entry.frame() {
         Ok(frame) => {
             let pt_vaddr = visitor.get_vaddr(frame.start_address());
             let pt = unsafe { &mut *pt_vaddr.as_mut_ptr() };
-            visitor.visit_pd(pt)
+            visitor.visit_pt(pt, base)
+        }
+        Err(FrameError::HugeFrame) => {
+            let frame = PhysFrame::<Size2MiB>::containing_address(entry.addr());
+            visitor.visit_huge_page_2m(entry, frame, base)
         }
-        Err(FrameError::HugeFrame) => {} // 2MB page
         Err(FrameError::FrameNotPresent) => {}
     }
 }
 
-pub fn visit_mut_pt<V: PageTableVisitMut>(visitor: &mut V, pt: &mut PageTable) {
-    pt.iter_mut()
-        .for_each(|entry| visitor.visit_pt_entry(entry))
+pub fn visit_mut_pt<V: PageTableVisitMut>(visitor: &mut V, pt: &mut PageTable, base: VirtAddr) {
+    for (index, entry) in pt.iter_mut().enumerate() {
+        visitor.visit_pt_entry(index, entry, base)
+    }
 }
 
-pub fn visit_mut_pt_entry<V: PageTableVisitMut>(visitor: &mut V, entry: &mut PageTableEntry) {
+pub fn visit_mut_pt_entry<V: PageTableVisitMut>(
+    visitor: &mut V,
+    index: usize,
+    entry: &mut PageTableEntry,
+    base: VirtAddr,
+) {
+    let base = index_to_vaddr(base, PT_SHIFT, index);
     match entry.frame() {
-        Ok(frame) => {}                               // 4KB page
+        Ok(frame) => visitor.visit_page_4k(entry, frame, base),
         Err(FrameError::HugeFrame) => unreachable!(), // Not huge any more...
         Err(FrameError::FrameNotPresent) => {}
     }
 }
+
+/// Returns the table reached through `table`'s entry at `index`, allocating and zeroing a fresh
+/// frame for it first if the entry is not yet present. A freshly-created entry carries
+/// `USER_ACCESSIBLE` from `flags` in addition to `PRESENT | WRITABLE`, so that a `map()` call
+/// installing a user-accessible leaf isn't silently downgraded to supervisor-only by an
+/// intermediate table that doesn't also carry the bit.
+fn ensure_table<'t, V: PageTableVisitMut, A: FrameAllocator<Size4KiB>>(
+    visitor: &mut V,
+    table: &'t mut PageTable,
+    index: usize,
+    flags: PageTableFlags,
+    frame_alloc: &mut A,
+) -> &'t mut PageTable {
+    let entry = table.iter_mut().nth(index).unwrap();
+    if entry.is_unused() {
+        let frame = frame_alloc
+            .allocate_frame()
+            .expect("ensure_table: out of physical memory");
+        let vaddr = visitor.get_vaddr(frame.start_address());
+        let new_table: &mut PageTable = unsafe { &mut *vaddr.as_mut_ptr() };
+        new_table.zero();
+        entry.set_addr(
+            frame.start_address(),
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | (flags & PageTableFlags::USER_ACCESSIBLE),
+        );
+    }
+    let frame = entry
+        .frame()
+        .expect("ensure_table: entry is present but huge where a table was expected");
+    let vaddr = visitor.get_vaddr(frame.start_address());
+    unsafe { &mut *vaddr.as_mut_ptr() }
+}
+
+/// Maps the single page at `addr` to `phys` at `size` granularity, creating any missing
+/// intermediate tables along the way.
+fn map_one<V: PageTableVisitMut, A: FrameAllocator<Size4KiB>>(
+    visitor: &mut V,
+    pml4: &mut PageTable,
+    addr: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+    size: MapPageSize,
+    frame_alloc: &mut A,
+) {
+    let pdpt = ensure_table(
+        visitor,
+        pml4,
+        table_index(addr, PML4_SHIFT),
+        flags,
+        frame_alloc,
+    );
+
+    if size == MapPageSize::Size1GiB {
+        let entry = pdpt.iter_mut().nth(table_index(addr, PDPT_SHIFT)).unwrap();
+        entry.set_addr(
+            phys,
+            flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE,
+        );
+        return;
+    }
+    let pd = ensure_table(
+        visitor,
+        pdpt,
+        table_index(addr, PDPT_SHIFT),
+        flags,
+        frame_alloc,
+    );
+
+    if size == MapPageSize::Size2MiB {
+        let entry = pd.iter_mut().nth(table_index(addr, PD_SHIFT)).unwrap();
+        entry.set_addr(
+            phys,
+            flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE,
+        );
+        return;
+    }
+    let pt = ensure_table(visitor, pd, table_index(addr, PD_SHIFT), flags, frame_alloc);
+
+    let entry = pt.iter_mut().nth(table_index(addr, PT_SHIFT)).unwrap();
+    entry.set_addr(phys, flags | PageTableFlags::PRESENT);
+}
+
+/// Installs `range` at `size` granularity, mapping each page to the corresponding physical frame
+/// starting at `phys_start`. See [`PageTableVisitMut::map`]. 4-level only: does not install or
+/// walk through a PML5 level even under `la57`.
+///
+/// `range` must start and end on a `size` boundary. Panics otherwise, since a misaligned range
+/// would otherwise have its last entry installed past `range.end`, silently clobbering whatever
+/// was mapped there.
+pub fn map<V: PageTableVisitMut, A: FrameAllocator<Size4KiB>>(
+    visitor: &mut V,
+    pml4: &mut PageTable,
+    range: PageRange,
+    phys_start: PhysAddr,
+    flags: PageTableFlags,
+    size: MapPageSize,
+    frame_alloc: &mut A,
+) {
+    let step = size.bytes();
+    let mut virt = range.start.start_address().as_u64();
+    let end = range.end.start_address().as_u64();
+    let mut phys = phys_start.as_u64();
+    assert!(
+        virt % step == 0 && end % step == 0,
+        "map: range {:#x}..{:#x} is not aligned to {:?}",
+        virt,
+        end,
+        size
+    );
+    while virt < end {
+        map_one(
+            visitor,
+            pml4,
+            VirtAddr::new(virt),
+            PhysAddr::new(phys),
+            flags,
+            size,
+            frame_alloc,
+        );
+        virt += step;
+        phys += step;
+    }
+}
+
+/// Clears the single entry mapping `addr`, whatever level it lives at, freeing any intermediate
+/// table that becomes empty as a result. Returns the size of the mapping that was cleared, or
+/// `None` if `addr` was not mapped. Panics if `addr` falls in the middle of a huge page rather
+/// than at its start, since partial unmapping of a huge page is not supported.
+fn unmap_one<V: PageTableVisitMut, D: FrameDeallocator<Size4KiB>>(
+    visitor: &mut V,
+    pml4: &mut PageTable,
+    addr: VirtAddr,
+    frame_dealloc: &mut D,
+) -> Option<MapPageSize> {
+    let p4i = table_index(addr, PML4_SHIFT);
+    let p3i = table_index(addr, PDPT_SHIFT);
+    let p2i = table_index(addr, PD_SHIFT);
+    let p1i = table_index(addr, PT_SHIFT);
+
+    let pml4_frame = {
+        let entry = pml4.iter_mut().nth(p4i).unwrap();
+        if entry.is_unused() {
+            return None;
+        }
+        entry
+            .frame()
+            .expect("unmap_one: PML4 entries are never huge")
+    };
+    let pdpt_vaddr = visitor.get_vaddr(pml4_frame.start_address());
+    let pdpt: &mut PageTable = unsafe { &mut *pdpt_vaddr.as_mut_ptr() };
+
+    let pdpt_huge = {
+        let entry = pdpt.iter_mut().nth(p3i).unwrap();
+        if entry.is_unused() {
+            return None;
+        }
+        let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if huge {
+            assert!(
+                addr.as_u64() % MapPageSize::Size1GiB.bytes() == 0,
+                "unmap_one: {:?} is not the start of its 1 GiB mapping; partial unmap of a huge page is not supported",
+                addr
+            );
+            entry.set_unused();
+        }
+        huge
+    };
+
+    let size = if pdpt_huge {
+        MapPageSize::Size1GiB
+    } else {
+        let pd_frame = pdpt.iter().nth(p3i).unwrap().frame().unwrap();
+        let pd_vaddr = visitor.get_vaddr(pd_frame.start_address());
+        let pd: &mut PageTable = unsafe { &mut *pd_vaddr.as_mut_ptr() };
+
+        let pd_huge = {
+            let entry = pd.iter_mut().nth(p2i).unwrap();
+            if entry.is_unused() {
+                return None;
+            }
+            let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+            if huge {
+                assert!(
+                    addr.as_u64() % MapPageSize::Size2MiB.bytes() == 0,
+                    "unmap_one: {:?} is not the start of its 2 MiB mapping; partial unmap of a huge page is not supported",
+                    addr
+                );
+                entry.set_unused();
+            }
+            huge
+        };
+
+        let size = if pd_huge {
+            MapPageSize::Size2MiB
+        } else {
+            let pt_frame = pd.iter().nth(p2i).unwrap().frame().unwrap();
+            let pt_vaddr = visitor.get_vaddr(pt_frame.start_address());
+            let pt: &mut PageTable = unsafe { &mut *pt_vaddr.as_mut_ptr() };
+
+            let pt_entry = pt.iter_mut().nth(p1i).unwrap();
+            if pt_entry.is_unused() {
+                return None;
+            }
+            pt_entry.set_unused();
+
+            if pt.iter().all(PageTableEntry::is_unused) {
+                unsafe { frame_dealloc.deallocate_frame(pt_frame) };
+                pd.iter_mut().nth(p2i).unwrap().set_unused();
+            }
+
+            MapPageSize::Size4KiB
+        };
+
+        if pd.iter().all(PageTableEntry::is_unused) {
+            unsafe { frame_dealloc.deallocate_frame(pd_frame) };
+            pdpt.iter_mut().nth(p3i).unwrap().set_unused();
+        }
+
+        size
+    };
+
+    if pdpt.iter().all(PageTableEntry::is_unused) {
+        unsafe { frame_dealloc.deallocate_frame(pml4_frame) };
+        pml4.iter_mut().nth(p4i).unwrap().set_unused();
+    }
+
+    Some(size)
+}
+
+/// Clears every entry covering `range`. See [`PageTableVisitMut::unmap`].
+///
+/// `range` must not end in the middle of a huge page: each 1 GiB/2 MiB mapping it touches must be
+/// fully contained in `range`, since partial unmapping of a huge page is not supported. Panics
+/// otherwise. 4-level only: does not walk through a PML5 level even under `la57`.
+pub fn unmap<V: PageTableVisitMut, D: FrameDeallocator<Size4KiB>>(
+    visitor: &mut V,
+    pml4: &mut PageTable,
+    range: PageRange,
+    frame_dealloc: &mut D,
+) {
+    let mut virt = range.start.start_address().as_u64();
+    let end = range.end.start_address().as_u64();
+    while virt < end {
+        let step = match unmap_one(visitor, pml4, VirtAddr::new(virt), frame_dealloc) {
+            Some(size) => {
+                let bytes = size.bytes();
+                assert!(
+                    virt + bytes <= end,
+                    "unmap: range ends in the middle of a {:?} mapping at {:#x}; partial unmap of a huge page is not supported",
+                    size,
+                    virt
+                );
+                bytes
+            }
+            None => MapPageSize::Size4KiB.bytes(),
+        };
+        virt += step;
+    }
+}
+
+/// A single coalesced mapping, as produced by [`MappingDumper`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub virt_start: VirtAddr,
+    pub virt_end: VirtAddr,
+    pub phys_start: PhysAddr,
+    pub flags: PageTableFlags,
+    pub size: MapPageSize,
+}
+
+/// A [`PageTableVisit`] that walks a page table hierarchy and coalesces adjacent leaf mappings
+/// that share `flags` and `size` and are virtually and physically contiguous into a single
+/// [`MappedRange`].
+///
+/// `get_page` resolves physical frames to virtual addresses, exactly as for any other
+/// [`PageTableVisit`]. `on_range` is called once per coalesced range, in ascending virtual
+/// address order, as soon as it is known to be complete. 4-level only: [`Self::dump`] always
+/// starts from the PML4 root and does not consult a PML5 level even under `la57`.
+pub struct MappingDumper<G, F> {
+    get_page: G,
+    on_range: F,
+    current: Option<MappedRange>,
+}
+
+impl<G, F> MappingDumper<G, F>
+where
+    G: FnMut(PhysFrame) -> Page<Size4KiB>,
+    F: FnMut(MappedRange),
+{
+    pub fn new(get_page: G, on_range: F) -> Self {
+        MappingDumper {
+            get_page,
+            on_range,
+            current: None,
+        }
+    }
+
+    /// Walks `pml4`, calling `on_range` for every coalesced mapping found, including the final
+    /// one still in progress once the walk completes.
+    pub fn dump(mut self, pml4: &PageTable) {
+        self.visit_pml4(pml4);
+        if let Some(last) = self.current.take() {
+            (self.on_range)(last);
+        }
+    }
+
+    fn record(
+        &mut self,
+        virt_start: VirtAddr,
+        phys_start: PhysAddr,
+        flags: PageTableFlags,
+        size: MapPageSize,
+    ) {
+        let virt_end = VirtAddr::new(virt_start.as_u64() + size.bytes());
+        if let Some(cur) = &mut self.current {
+            let contiguous = cur.virt_end == virt_start
+                && cur.flags == flags
+                && cur.size == size
+                && cur.phys_start.as_u64() + (cur.virt_end.as_u64() - cur.virt_start.as_u64())
+                    == phys_start.as_u64();
+            if contiguous {
+                cur.virt_end = virt_end;
+                return;
+            }
+            (self.on_range)(self.current.take().unwrap());
+        }
+        self.current = Some(MappedRange {
+            virt_start,
+            virt_end,
+            phys_start,
+            flags,
+            size,
+        });
+    }
+}
+
+impl<G, F> PageTableVisit for MappingDumper<G, F>
+where
+    G: FnMut(PhysFrame) -> Page<Size4KiB>,
+    F: FnMut(MappedRange),
+{
+    fn get_page(&mut self, paddr: PhysFrame) -> Page<Size4KiB> {
+        (self.get_page)(paddr)
+    }
+
+    fn visit_huge_page_1g(
+        &mut self,
+        entry: &PageTableEntry,
+        frame: PhysFrame<Size1GiB>,
+        base: VirtAddr,
+    ) {
+        self.record(
+            base,
+            frame.start_address(),
+            entry.flags(),
+            MapPageSize::Size1GiB,
+        );
+    }
+
+    fn visit_huge_page_2m(
+        &mut self,
+        entry: &PageTableEntry,
+        frame: PhysFrame<Size2MiB>,
+        base: VirtAddr,
+    ) {
+        self.record(
+            base,
+            frame.start_address(),
+            entry.flags(),
+            MapPageSize::Size2MiB,
+        );
+    }
+
+    fn visit_page_4k(
+        &mut self,
+        entry: &PageTableEntry,
+        frame: PhysFrame<Size4KiB>,
+        base: VirtAddr,
+    ) {
+        self.record(
+            base,
+            frame.start_address(),
+            entry.flags(),
+            MapPageSize::Size4KiB,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    fn leak_table() -> &'static mut PageTable {
+        Box::leak(Box::new(PageTable::new()))
+    }
+
+    /// A [`PageTableVisit`]/[`PageTableVisitMut`] that treats physical and virtual addresses as
+    /// numerically identical, so that frames handed out by [`BumpFrameAllocator`] (which are
+    /// really just the addresses of leaked [`PageTable`]s) resolve straight back to them.
+    struct IdentityMapper;
+
+    impl PageTableVisit for IdentityMapper {
+        fn get_page(&mut self, paddr: PhysFrame) -> Page<Size4KiB> {
+            Page::containing_address(VirtAddr::new(paddr.start_address().as_u64()))
+        }
+    }
+
+    impl PageTableVisitMut for IdentityMapper {
+        fn get_vaddr(&mut self, paddr: PhysAddr) -> VirtAddr {
+            VirtAddr::new(paddr.as_u64())
+        }
+    }
+
+    /// A frame "allocator" that leaks a fresh [`PageTable`] per allocation and reuses deallocated
+    /// frames, for use with [`IdentityMapper`] in tests.
+    struct BumpFrameAllocator {
+        freed: Vec<PhysFrame<Size4KiB>>,
+    }
+
+    impl BumpFrameAllocator {
+        fn new() -> Self {
+            BumpFrameAllocator { freed: Vec::new() }
+        }
+    }
+
+    impl FrameAllocator<Size4KiB> for BumpFrameAllocator {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+            if let Some(frame) = self.freed.pop() {
+                return Some(frame);
+            }
+            let table = leak_table();
+            Some(PhysFrame::containing_address(PhysAddr::new(
+                table as *mut PageTable as u64,
+            )))
+        }
+    }
+
+    impl FrameDeallocator<Size4KiB> for BumpFrameAllocator {
+        unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+            self.freed.push(frame);
+        }
+    }
+
+    #[test]
+    fn combine_flags_ands_permission_bits_but_ors_no_execute() {
+        let a =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let b = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+
+        let combined = combine_flags(a, b);
+
+        assert!(!combined.contains(PageTableFlags::WRITABLE));
+        assert!(!combined.contains(PageTableFlags::USER_ACCESSIBLE));
+        assert!(combined.contains(PageTableFlags::NO_EXECUTE));
+    }
+
+    #[test]
+    fn translate_ors_no_execute_set_only_at_the_leaf() {
+        let pml4 = leak_table();
+        let mut alloc = BumpFrameAllocator::new();
+        let mut mapper = IdentityMapper;
+
+        let virt = VirtAddr::new(0x1000);
+        let phys = PhysAddr::new(0x20_0000);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let range = PageRange {
+            start: Page::<Size4KiB>::containing_address(virt),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(
+                virt.as_u64() + MapPageSize::Size4KiB.bytes(),
+            )),
+        };
+        map(
+            &mut mapper,
+            pml4,
+            range,
+            phys,
+            flags,
+            MapPageSize::Size4KiB,
+            &mut alloc,
+        );
+
+        let (resolved_phys, size, resolved_flags) = translate(&mut mapper, pml4, virt).unwrap();
+        assert_eq!(resolved_phys, phys);
+        assert_eq!(size, MapPageSize::Size4KiB);
+        assert!(resolved_flags.contains(PageTableFlags::NO_EXECUTE));
+    }
+
+    #[test]
+    fn unmap_reclaims_intermediate_tables_once_the_last_leaf_is_cleared() {
+        let pml4 = leak_table();
+        let mut alloc = BumpFrameAllocator::new();
+        let mut mapper = IdentityMapper;
+
+        let virt = VirtAddr::new(0x1000);
+        let range = PageRange {
+            start: Page::<Size4KiB>::containing_address(virt),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(
+                virt.as_u64() + MapPageSize::Size4KiB.bytes(),
+            )),
+        };
+        map(
+            &mut mapper,
+            pml4,
+            range,
+            PhysAddr::new(0x20_0000),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            MapPageSize::Size4KiB,
+            &mut alloc,
+        );
+        assert!(translate(&mut mapper, pml4, virt).is_some());
+        assert!(alloc.freed.is_empty());
+
+        unmap(&mut mapper, pml4, range, &mut alloc);
+
+        assert!(translate(&mut mapper, pml4, virt).is_none());
+        // PDPT, PD and PT all become empty once their one entry is cleared, so all three
+        // intermediate frames should come back.
+        assert_eq!(alloc.freed.len(), 3);
+    }
+
+    #[test]
+    fn unmap_clears_a_1gib_huge_page_mapped_at_its_start() {
+        let pml4 = leak_table();
+        let mut alloc = BumpFrameAllocator::new();
+        let mut mapper = IdentityMapper;
+
+        let virt = VirtAddr::new(0);
+        let range = PageRange {
+            start: Page::<Size4KiB>::containing_address(virt),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(MapPageSize::Size1GiB.bytes())),
+        };
+        map(
+            &mut mapper,
+            pml4,
+            range,
+            PhysAddr::new(0x4000_0000),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            MapPageSize::Size1GiB,
+            &mut alloc,
+        );
+        assert!(translate(&mut mapper, pml4, virt).is_some());
+
+        unmap(&mut mapper, pml4, range, &mut alloc);
+
+        assert!(translate(&mut mapper, pml4, virt).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "partial unmap of a huge page is not supported")]
+    fn unmap_rejects_a_partial_1gib_huge_page_range() {
+        let pml4 = leak_table();
+        let mut alloc = BumpFrameAllocator::new();
+        let mut mapper = IdentityMapper;
+
+        let virt = VirtAddr::new(0);
+        let map_range = PageRange {
+            start: Page::<Size4KiB>::containing_address(virt),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(MapPageSize::Size1GiB.bytes())),
+        };
+        map(
+            &mut mapper,
+            pml4,
+            map_range,
+            PhysAddr::new(0x4000_0000),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            MapPageSize::Size1GiB,
+            &mut alloc,
+        );
+
+        // Only ask to unmap the first 4 KiB of the 1 GiB mapping.
+        let partial_range = PageRange {
+            start: Page::<Size4KiB>::containing_address(virt),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(MapPageSize::Size4KiB.bytes())),
+        };
+        unmap(&mut mapper, pml4, partial_range, &mut alloc);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not aligned to")]
+    fn map_rejects_a_range_not_aligned_to_size() {
+        let pml4 = leak_table();
+        let mut alloc = BumpFrameAllocator::new();
+        let mut mapper = IdentityMapper;
+
+        // Three 4 KiB pages is not a multiple of the 2 MiB granularity being requested.
+        let range = PageRange {
+            start: Page::<Size4KiB>::containing_address(VirtAddr::new(0)),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(
+                3 * MapPageSize::Size4KiB.bytes(),
+            )),
+        };
+        map(
+            &mut mapper,
+            pml4,
+            range,
+            PhysAddr::new(0x20_0000),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            MapPageSize::Size2MiB,
+            &mut alloc,
+        );
+    }
+
+    #[test]
+    fn mapping_dumper_coalesces_contiguous_same_flags_mappings() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let pml4 = leak_table();
+        let mut alloc = BumpFrameAllocator::new();
+        let mut mapper = IdentityMapper;
+
+        let base = VirtAddr::new(0x10_0000);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let range = PageRange {
+            start: Page::<Size4KiB>::containing_address(base),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(
+                base.as_u64() + 2 * MapPageSize::Size4KiB.bytes(),
+            )),
+        };
+        map(
+            &mut mapper,
+            pml4,
+            range,
+            PhysAddr::new(0x40_0000),
+            flags,
+            MapPageSize::Size4KiB,
+            &mut alloc,
+        );
+
+        let ranges = Rc::new(RefCell::new(Vec::new()));
+        let collected = Rc::clone(&ranges);
+        let dumper = MappingDumper::new(
+            |frame: PhysFrame| {
+                Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()))
+            },
+            move |r: MappedRange| collected.borrow_mut().push(r),
+        );
+        dumper.dump(pml4);
+
+        let ranges = ranges.borrow();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].virt_start, base);
+        assert_eq!(
+            ranges[0].virt_end,
+            VirtAddr::new(base.as_u64() + 2 * MapPageSize::Size4KiB.bytes())
+        );
+        assert_eq!(ranges[0].flags, flags | PageTableFlags::PRESENT);
+    }
+
+    #[test]
+    fn mapping_dumper_does_not_coalesce_across_a_flags_change() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let pml4 = leak_table();
+        let mut alloc = BumpFrameAllocator::new();
+        let mut mapper = IdentityMapper;
+
+        let base = VirtAddr::new(0x10_0000);
+        let page_size = MapPageSize::Size4KiB.bytes();
+        let first_range = PageRange {
+            start: Page::<Size4KiB>::containing_address(base),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(base.as_u64() + page_size)),
+        };
+        let second_range = PageRange {
+            start: Page::<Size4KiB>::containing_address(VirtAddr::new(base.as_u64() + page_size)),
+            end: Page::<Size4KiB>::containing_address(VirtAddr::new(base.as_u64() + 2 * page_size)),
+        };
+        map(
+            &mut mapper,
+            pml4,
+            first_range,
+            PhysAddr::new(0x40_0000),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            MapPageSize::Size4KiB,
+            &mut alloc,
+        );
+        map(
+            &mut mapper,
+            pml4,
+            second_range,
+            PhysAddr::new(0x40_0000 + page_size),
+            PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            MapPageSize::Size4KiB,
+            &mut alloc,
+        );
+
+        let ranges = Rc::new(RefCell::new(Vec::new()));
+        let collected = Rc::clone(&ranges);
+        let dumper = MappingDumper::new(
+            |frame: PhysFrame| {
+                Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()))
+            },
+            move |r: MappedRange| collected.borrow_mut().push(r),
+        );
+        dumper.dump(pml4);
+
+        assert_eq!(ranges.borrow().len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "la57")]
+    fn index_to_vaddr_la57_contributes_the_index_instead_of_being_truncated_away() {
+        assert_eq!(index_to_vaddr_la57(VirtAddr::new(0), 0), VirtAddr::new(0));
+        assert_eq!(
+            index_to_vaddr_la57(VirtAddr::new(0), 5),
+            unsafe { VirtAddr::new_unsafe(5u64 << PML5_SHIFT) }
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "la57")]
+    fn visit_pml5_reconstructs_a_distinct_base_per_index() {
+        struct RecordingVisitor {
+            bases: Vec<VirtAddr>,
+        }
+
+        impl PageTableVisit for RecordingVisitor {
+            fn get_page(&mut self, paddr: PhysFrame) -> Page<Size4KiB> {
+                Page::containing_address(VirtAddr::new(paddr.start_address().as_u64()))
+            }
+
+            fn visit_pml4_entry(&mut self, index: usize, _entry: &PageTableEntry, base: VirtAddr) {
+                if index == 0 {
+                    self.bases.push(base);
+                }
+            }
+        }
+
+        let pml5 = leak_table();
+        let pml4_a = leak_table();
+        let pml4_b = leak_table();
+
+        pml5.iter_mut().nth(0).unwrap().set_addr(
+            PhysAddr::new(pml4_a as *mut PageTable as u64),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+        pml5.iter_mut().nth(5).unwrap().set_addr(
+            PhysAddr::new(pml4_b as *mut PageTable as u64),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+
+        let mut visitor = RecordingVisitor { bases: Vec::new() };
+        visitor.visit_pml5(pml5);
+
+        assert_eq!(visitor.bases.len(), 2);
+        assert_eq!(visitor.bases[0], VirtAddr::new(0));
+        assert_eq!(
+            visitor.bases[1],
+            unsafe { VirtAddr::new_unsafe(5u64 << PML5_SHIFT) }
+        );
+    }
+}